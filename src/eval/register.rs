@@ -0,0 +1,141 @@
+//! Host-function registration and the default standard library.
+//!
+//! Mirrors rhai's `RegisterFn` pattern: an embedder binds a Rust closure into the global
+//! scope with `register_fn`, and `register_stdlib` seeds the handful of globals
+//! (`console.log`, `Math.floor/sqrt/random`, `parseInt`, `parseFloat`, `String`, `Number`)
+//! that scripts expect to find already defined when they start running.
+
+use std::cell::RefCell;
+
+use eval::{shared_fn, ScopeState, SendSync};
+
+use jsrs_common::types::coerce::AsNumber;
+use jsrs_common::types::js_obj::JsObjStruct;
+use jsrs_common::types::js_str::JsStrStruct;
+use jsrs_common::types::js_var::{JsVar, JsType, JsPtrEnum, JsPtrTag, JsKey};
+use jsrs_common::types::js_var::JsType::*;
+use jsrs_common::types::native_fn::NativeFn;
+use french_press::alloc::AllocBox;
+
+use var::{scalar, JsVarValue};
+
+/// Bind a native Rust function into `state`'s global scope under `name`.
+///
+/// `f` receives the call's `ScopeManager` handle, an optional receiver (the `Call` arm
+/// passes `None` for every call, including `receiver.name(...)`), and the evaluated
+/// argument list, and
+/// returns the call's result -- the same signature the `Call` arm already expects of
+/// `JsPtrEnum::NativeFn`.
+pub fn register_fn<F>(state: &ScopeState, name: &str, f: F)
+    where F: Fn(ScopeState, Option<JsVarValue>, Vec<JsVarValue>) -> JsVarValue + SendSync + 'static
+{
+    let (var, ptr) = native_fn_var(name, f);
+    write_state!(state).alloc(var, Some(ptr)).expect("Unable to register native function");
+}
+
+/// Build a `(JsVar, JsPtrEnum)` pair wrapping `f` as a `NativeFn`, suitable for storing
+/// directly in the global scope or as a field of a native namespace object (e.g. `Math`).
+fn native_fn_var<F>(name: &str, f: F) -> (JsVar, JsPtrEnum)
+    where F: Fn(ScopeState, Option<JsVarValue>, Vec<JsVarValue>) -> JsVarValue + SendSync + 'static
+{
+    let var = JsVar::bind(name, JsType::JsPtr(JsPtrTag::NativeFn));
+    let ptr = JsPtrEnum::NativeFn(NativeFn::new(shared_fn(f)));
+    (var, ptr)
+}
+
+/// Build a plain native namespace object (like `Math` or `console`) out of named native
+/// functions, the same way an `Object` literal is built in `eval_exp`.
+fn native_namespace(fields: Vec<(&str, (JsVar, JsPtrEnum))>) -> (JsVar, JsPtrEnum) {
+    let mut kv_tuples = Vec::new();
+    for (name, (var, ptr)) in fields {
+        let key = JsKey::JsStr(JsStrStruct::new(name));
+        kv_tuples.push((key, var, Some(ptr)));
+    }
+    let obj = JsObjStruct::new(None, "", kv_tuples, &mut AllocBox::new());
+    (JsVar::new(JsType::JsPtr(JsPtrTag::JsObj)), JsPtrEnum::JsObj(obj))
+}
+
+/// Seed `state`'s global scope with the default standard library.
+///
+/// This gives scripts `console.log`, `Math.floor/sqrt/random`, `parseInt`, `parseFloat`,
+/// and `String`/`Number` coercions, without which `eval_string` has no way to produce
+/// observable output or talk to native code at all.
+pub fn register_stdlib(state: &ScopeState) {
+    let console = native_namespace(vec![
+        ("log", native_fn_var("log", |_, _, args| {
+            let rendered: Vec<String> = args.iter().map(render_value).collect();
+            println!("{}", rendered.join(" "));
+            scalar(JsUndef)
+        })),
+    ]);
+    write_state!(state).alloc(JsVar::bind("console", console.0.t.clone()), Some(console.1))
+        .expect("Unable to register console");
+
+    let math = native_namespace(vec![
+        ("floor", native_fn_var("floor", |_, _, args| {
+            scalar(JsNum(arg_number(&args, 0).floor()))
+        })),
+        ("sqrt", native_fn_var("sqrt", |_, _, args| {
+            scalar(JsNum(arg_number(&args, 0).sqrt()))
+        })),
+        ("random", native_fn_var("random", |_, _, _| {
+            scalar(JsNum(next_random()))
+        })),
+    ]);
+    write_state!(state).alloc(JsVar::bind("Math", math.0.t.clone()), Some(math.1))
+        .expect("Unable to register Math");
+
+    register_fn(state, "parseInt", |_, _, args| {
+        let n = arg_number(&args, 0).trunc();
+        scalar(JsNum(n))
+    });
+
+    register_fn(state, "parseFloat", |_, _, args| {
+        scalar(JsNum(arg_number(&args, 0)))
+    });
+
+    register_fn(state, "String", |_, _, args| {
+        let var = JsVar::new(JsType::JsPtr(JsPtrTag::JsStr));
+        let rendered = args.get(0).map_or(String::new(), render_value);
+        (var, Some(JsPtrEnum::JsStr(JsStrStruct::new(&rendered))))
+    });
+
+    register_fn(state, "Number", |_, _, args| {
+        scalar(JsNum(arg_number(&args, 0)))
+    });
+}
+
+/// Coerce the argument at `idx` (or `0.0` if missing) to a number, following the same
+/// `AsNumber` coercion used for binary operators and increment/decrement.
+fn arg_number(args: &[JsVarValue], idx: usize) -> f64 {
+    args.get(idx).map_or(0.0, |a| a.0.as_number())
+}
+
+/// Render a `JsVarValue` the way `console.log`/`String()` would: for heap-backed values
+/// the real content lives in `a.1: Option<JsPtrEnum>`, not the pointer tag in `a.0.t`, so
+/// this matches on the payload instead of `Debug`-printing the tag. Scalars (numbers,
+/// bools, `null`/`undefined`) have no payload and fall back to their `JsType`. There's no
+/// `JsPtrEnum::JsArray` arm: this evaluator has no array support at all yet (see the
+/// scoping note in `eval::eval_exp`).
+fn render_value(a: &JsVarValue) -> String {
+    match a.1 {
+        Some(JsPtrEnum::JsStr(ref s)) => s.text.clone(),
+        Some(JsPtrEnum::JsObj(_)) => String::from("[object Object]"),
+        Some(JsPtrEnum::JsFn(_)) | Some(JsPtrEnum::NativeFn(_)) => String::from("[object Function]"),
+        None => format!("{:?}", a.0.t),
+    }
+}
+
+thread_local!(static RNG_STATE: RefCell<u64> = RefCell::new(0x2545F4914F6CDD1D));
+
+/// A small xorshift64 PRNG so `Math.random` doesn't need to pull in an external crate.
+fn next_random() -> f64 {
+    RNG_STATE.with(|state| {
+        let mut x = *state.borrow();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *state.borrow_mut() = x;
+        (x >> 11) as f64 / ((1u64 << 53) as f64)
+    })
+}
@@ -0,0 +1,76 @@
+//! Source spans and diagnostic rendering.
+//!
+//! **This module doc is the one place that explains a constraint several other items in
+//! this crate run into -- look here first, then follow the `(see eval::span)` references
+//! back out to them, instead of re-reading the same paragraph at each site.**
+//!
+//! This tree is a snapshot of the evaluator alone: `jsrs_common` (the AST and the
+//! heap-value types `JsVar`/`JsPtrEnum`/`JsPtrTag`) and `jsrs_parser` (which builds the
+//! AST from source text) are both external crates that this tree depends on but does not
+//! contain. Anything that requires *adding a variant* to one of their types -- a new
+//! `Stmt`/`Exp` case, a new `JsPtrEnum`/`JsPtrTag` case, a `Span` field on every AST node --
+//! can't be implemented here no matter how the evaluator-side code is written, because
+//! there is no upstream source to change. Concretely, as of this tree: `Stmt::Break`/
+//! `Continue` don't exist (see `eval::Completion`'s doc); neither do `Exp::ArrayLit`/
+//! `Exp::Index`/`Stmt::IndexAssign`, nor a `JsPtrEnum::JsArray`/`JsPtrTag::JsArray` to
+//! evaluate them into (see the scoping note above `&Null` in `eval_exp`); and AST nodes
+//! carry no span, so `JsError` (defined in this tree, in `js_error`) can't usefully carry
+//! one either (see the note inside `js_error::JsError`). Where an earlier pass assumed one of these upstream additions had happened and
+//! wrote code against it, that code has been removed rather than left as permanently-dead
+//! or non-compiling scaffolding -- check each referenced doc comment for specifics.
+//!
+//! What *can* land, and does, is the renderer below: a pure function from `(source, span,
+//! message)` to a one-line excerpt with a caret underline, independent of how the span was
+//! obtained. It has no caller in `eval_stmt`/`eval_exp` yet -- there's no AST span to pass
+//! it until the upstream change above lands -- so for now it's exercised directly (see
+//! `eval::test::test_render_error_underlines_span`) rather than wired into `JsError`. A
+//! `JsError::Spanned` wrapper was deliberately *not* added for this: a variant no call site
+//! can construct is dead weight, and it would also need every existing `match` on `JsError`
+//! (e.g. the `Try` catch arm's `Err(JsError::JsVar(..))` pattern) updated to see through it.
+
+use std::fmt;
+
+/// A byte-offset range into the original source string, `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start: start, end: end }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+/// Render `message` as a one-line source excerpt with a caret underline beneath `span`,
+/// in the style of `rustc`/ariadne diagnostics: the offending line, then a line of spaces
+/// and `^` characters pointing at the span.
+///
+/// `span` is clamped to the bounds of `source` so a slightly-stale span (e.g. from a source
+/// edit) can't panic on a slice out of range.
+pub fn render_error(source: &str, span: Span, message: &str) -> String {
+    let start = span.start.min(source.len());
+    let end = span.end.min(source.len()).max(start);
+
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+    let line = &source[line_start..line_end];
+
+    let caret_offset = start - line_start;
+    let caret_len = (end - start).max(1);
+
+    format!(
+        "{}\n{}{}\n{}",
+        line,
+        " ".repeat(caret_offset),
+        "^".repeat(caret_len),
+        message,
+    )
+}
@@ -0,0 +1,95 @@
+//! Shared interpreter state, single- or multi-threaded.
+//!
+//! By default the interpreter confines itself to one thread via `Rc<RefCell<ScopeManager>>`.
+//! Enabling the `sync` feature (mirroring rhai's own `sync` feature) swaps that for
+//! `Arc<Mutex<ScopeManager>>` instead, so a host can clone the handle across `thread::spawn`
+//! and run independent scripts concurrently against one engine. `eval_string`/`eval_stmt`/
+//! `eval_exp` are written against the `Shared`/`ScopeState` aliases and the `read_state!`/
+//! `write_state!` accessors below, so the same source compiles either way.
+
+#[cfg(not(feature = "sync"))]
+use std::cell::{Ref, RefCell, RefMut};
+#[cfg(not(feature = "sync"))]
+use std::rc::Rc;
+
+#[cfg(feature = "sync")]
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[cfg(not(feature = "sync"))]
+pub type Shared<T> = Rc<RefCell<T>>;
+#[cfg(feature = "sync")]
+pub type Shared<T> = Arc<Mutex<T>>;
+
+/// The handle type threaded through `eval_string`/`eval_stmt`/`eval_exp`: `Rc<RefCell<_>>`
+/// by default, `Arc<Mutex<_>>` under the `sync` feature.
+pub type ScopeState = Shared<::french_press::ScopeManager>;
+
+#[cfg(not(feature = "sync"))]
+pub fn shared<T>(val: T) -> Shared<T> {
+    Rc::new(RefCell::new(val))
+}
+#[cfg(feature = "sync")]
+pub fn shared<T>(val: T) -> Shared<T> {
+    Arc::new(Mutex::new(val))
+}
+
+/// Marker bound for anything stored behind a `Shared`-style pointer: no extra requirement
+/// by default, `Send + Sync` under the `sync` feature (mirroring rhai's own `SendSync`
+/// trait alias, used the same way to gate closure bounds on its `sync` feature).
+#[cfg(not(feature = "sync"))]
+pub trait SendSync {}
+#[cfg(not(feature = "sync"))]
+impl<T> SendSync for T {}
+
+#[cfg(feature = "sync")]
+pub trait SendSync: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: Send + Sync> SendSync for T {}
+
+/// The pointer type native-function callbacks are stored behind: `Rc<F>` by default,
+/// `Arc<F>` under the `sync` feature -- so a `NativeFn` is only ever actually `Send` (and
+/// a `ScopeState` embedding one can only actually cross `thread::spawn`) when it was built
+/// with `shared_fn`, not a raw `Rc::new`.
+#[cfg(not(feature = "sync"))]
+pub type SharedFn<F> = Rc<F>;
+#[cfg(feature = "sync")]
+pub type SharedFn<F> = Arc<F>;
+
+#[cfg(not(feature = "sync"))]
+pub fn shared_fn<F: SendSync + 'static>(f: F) -> SharedFn<F> {
+    Rc::new(f)
+}
+#[cfg(feature = "sync")]
+pub fn shared_fn<F: SendSync + 'static>(f: F) -> SharedFn<F> {
+    Arc::new(f)
+}
+
+#[cfg(not(feature = "sync"))]
+pub fn read<T>(state: &Shared<T>) -> Ref<T> {
+    state.borrow()
+}
+#[cfg(feature = "sync")]
+pub fn read<T>(state: &Shared<T>) -> MutexGuard<T> {
+    state.lock().expect("ScopeManager mutex poisoned")
+}
+
+#[cfg(not(feature = "sync"))]
+pub fn write<T>(state: &Shared<T>) -> RefMut<T> {
+    state.borrow_mut()
+}
+#[cfg(feature = "sync")]
+pub fn write<T>(state: &Shared<T>) -> MutexGuard<T> {
+    state.lock().expect("ScopeManager mutex poisoned")
+}
+
+/// Borrow `$state` for reading: `state.borrow()` under the default build, `state.lock()`
+/// under `sync`.
+macro_rules! read_state {
+    ($state:expr) => { $crate::eval::sync::read(&$state) };
+}
+
+/// Borrow `$state` for writing: `state.borrow_mut()` under the default build, `state.lock()`
+/// under `sync`.
+macro_rules! write_state {
+    ($state:expr) => { $crate::eval::sync::write(&$state) };
+}
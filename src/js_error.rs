@@ -11,8 +11,17 @@ pub enum JsError {
     GcError(GcError),
     TypeError(String),
     ReferenceError(String),
+    SyntaxError(String),
+    RangeError(String),
     JsVar(JsVarValue),
     UnimplementedError(String),
+    // A `Spanned(Span, Box<JsError>)` variant (to tag any of the above with the source
+    // range it occurred at, rendered via `eval::span::render_error`) isn't included here --
+    // see `eval::span`. A variant with no call site that could ever construct it is worse
+    // than no variant: every `match` on `JsError` (e.g. the `Try` catch arm's
+    // `Err(JsError::JsVar(..))` pattern in `eval::eval_stmt`) would need to also handle an
+    // unreachable `Spanned` wrapper, or silently stop matching values that later get
+    // wrapped in one.
 }
 
 impl JsError {
@@ -32,6 +41,8 @@ impl fmt::Display for JsError {
             JsError::GcError(ref gc) => write!(f, "GcError: {}", gc),
             JsError::TypeError(ref s) => write!(f, "TypeError: {}", s),
             JsError::ReferenceError(ref s) => write!(f, "ReferenceError: {}", s),
+            JsError::SyntaxError(ref s) => write!(f, "SyntaxError: {}", s),
+            JsError::RangeError(ref s) => write!(f, "RangeError: {}", s),
             JsError::JsVar(ref var_value) => write!(f, "{:?}", var_value),
             JsError::UnimplementedError(ref s) => write!(f, "UnimplementedError: {}", s),
         }